@@ -1,33 +1,194 @@
 from collections import namedtuple
 from hashlib import sha256
+from os import getenv, replace
 from pathlib import Path
-from typing import List
+from shutil import move
+from threading import Event
+from typing import Dict, List, Optional
 
 from boto3 import client
 from botocore import UNSIGNED
 from botocore.config import Config
 from tqdm import tqdm
 
+S3_MAX_RETRIES = int(getenv("S3_MAX_RETRIES", "4"))
+
 S3FileInfo = namedtuple("S3FileInfo", ["name", "size"])
 
+BUCKET = "discogs-data-dumps"
+
+
+class DownloadStopped(Exception):
+    pass
+
+
+def s3_client():
+    # The "standard" retry mode retries throttling and transient 5xx errors with jittered backoff.
+    retries = {"max_attempts": S3_MAX_RETRIES, "mode": "standard"}
+    return client(
+        "s3", region_name="us-west-2", config=Config(signature_version=UNSIGNED, retries=retries)
+    )
+
+
+def check_discogs_bucket():
+    # Raises if the bucket can't be reached or read, without downloading anything.
+    s3_client().list_objects_v2(Bucket=BUCKET, Prefix="data/", MaxKeys=1)
+
+
+def download_file(
+    s3,
+    bucket: str,
+    s3file: S3FileInfo,
+    output_directory: str,
+    temp_directory: str,
+    stop: Optional[Event] = None,
+) -> str:
+    filename = s3file.name.split("/")[2]
+
+    def progress(t):
+        def inner(bytes_amount):
+            # Raising from the callback aborts the transfer, rather than finishing a multi-GB file.
+            if stop is not None and stop.is_set():
+                raise DownloadStopped(filename)
+            t.update(bytes_amount)
+
+        return inner
+
+    # Download into the temp directory first, so only complete files appear in `output_directory`.
+    temp_path = Path(temp_directory, f"{filename}.part")
+    path = Path(output_directory, filename)
+    desc = f"{filename:33}"
+    bar_format = "{desc}{percentage:3.0f}%|{bar:80}{r_bar}"
+    try:
+        with temp_path.open("wb") as f:
+            with tqdm(
+                desc=desc,
+                bar_format=bar_format,
+                ncols=155,
+                total=s3file.size,
+                unit="B",
+                unit_scale=True,
+            ) as t:
+                s3.download_fileobj(bucket, s3file.name, f, Callback=progress(t))
+    except DownloadStopped:
+        temp_path.unlink(missing_ok=True)
+        raise
+    # Across filesystems `move` copies, which could leave a truncated file under the final name.
+    # So stage the file next to it first, and then rename it into place, which is atomic.
+    staged_path = path.with_name(f"{path.name}.part")
+    if temp_path != staged_path:
+        move(temp_path, staged_path)
+    replace(staged_path, path)
+
+    return file_checksum(path)
+
+
+def file_checksum(path: Path) -> str:
+    hash = sha256()
+    with path.open("rb") as f:
+        for byte_block in iter(lambda: f.read(4096), b""):
+            hash.update(byte_block)
+    return hash.hexdigest()
+
+
+# Maps the filenames listed in an export's `CHECKSUM.txt` to their SHA-256 checksums.
+def read_checksums(path: Path) -> Dict[str, str]:
+    checksums = {}
+    with path.open("r") as f:
+        while line := f.readline():
+            parts = line.strip().split(" ")
+            checksums[parts[1]] = parts[0]
+    return checksums
+
+
+def find_local_discogs_data(output_directory: str) -> List[str]:
+    print(" -=: Use the most recent local Discogs data :=- ")
+
+    ids = {}
 
-def download_discogs_data(output_directory: str) -> List[str]:
+    for path in Path(output_directory).glob("discogs_*_*.xml.gz"):
+        id = path.name.split("_")[1]
+        if id not in ids.keys():
+            ids[id] = []
+        ids[id].append(path.name)
+
+    # Always try to use the most recent Discogs export first, as long as all of its data is present.
+    for id in sorted(ids.keys(), reverse=True):
+        data = [
+            f"discogs_{id}_artists.xml.gz",
+            f"discogs_{id}_labels.xml.gz",
+            f"discogs_{id}_masters.xml.gz",
+            f"discogs_{id}_releases.xml.gz",
+        ]
+        if all(filename in ids[id] for filename in data):
+            return data
+
+    raise RuntimeError(f"no complete Discogs export found in {output_directory}")
+
+
+# Checks the most recent local export against its `CHECKSUM.txt`, e.g. after copying it to another
+# volume, without downloading anything. Returns whether all of its files are present and intact.
+def verify_discogs_data(output_directory: str) -> bool:
+    print(" -=: Verify the most recent local Discogs data :=- ")
+
+    checksum_files = sorted(Path(output_directory).glob("discogs_*_CHECKSUM.txt"), reverse=True)
+    if not checksum_files:
+        print(f" --: [❌] no CHECKSUM.txt found in {output_directory} [❌] :-- ")
+        return False
+
+    verified = True
+    for filename, expected in read_checksums(checksum_files[0]).items():
+        path = Path(output_directory, filename)
+        if not path.exists():
+            print(f"  [❓]: {filename:33} is missing")
+            verified = False
+        elif file_checksum(path) != expected:
+            print(f"  [❌]: checksum for {filename:33}")
+            verified = False
+        else:
+            print(f"  [✅]: checksum for {filename:33}")
+    return verified
+
+
+# Setting `stop` aborts the download with `DownloadStopped`, e.g. when the run is out of time.
+def download_discogs_data(
+    output_directory: str, temp_directory: Optional[str] = None, stop: Optional[Event] = None
+) -> List[str]:
     print(" -=: Download the most recent Discogs data :=- ")
 
-    bucket = "discogs-data-dumps"
-    s3 = client("s3", region_name="us-west-2", config=Config(signature_version=UNSIGNED))
-    response = s3.list_objects_v2(Bucket=bucket, Prefix="data/")
-    contents = response.get("Contents")
+    if temp_directory is None:
+        temp_directory = output_directory
+
+    s3 = s3_client()
+    # A single listing returns at most 1000 objects, which the exports outgrew long ago.
+    contents = []
+    for page in s3.get_paginator("list_objects_v2").paginate(Bucket=BUCKET, Prefix="data/"):
+        contents.extend(page.get("Contents", []))
+
+    # An empty listing means the bucket or prefix is wrong or unreadable, not that nothing is new.
+    if not contents:
+        raise RuntimeError(f"no objects found in s3://{BUCKET}/data/")
 
     ids = {}
 
     for content in contents:
         key = content["Key"]
         size = content["Size"]
+        filename = key.split("/")[2]
         id = key.split("_")[1]
         if id not in ids.keys():
-            ids[id] = []
-        ids[id].append(S3FileInfo(key, size))
+            ids[id] = {}
+
+        # An empty object is left behind by an interrupted upload, and would fail to decompress.
+        if size == 0:
+            print(f" --: [⚠️] skipping {key}, it's empty [⚠️] :-- ")
+            continue
+
+        # The same file of an export may be listed under more than one year directory. Group by
+        # filename, so it's only counted once, preferring the copy under the export's own year.
+        if filename in ids[id] and not key.startswith(f"data/{id[:4]}/"):
+            continue
+        ids[id][filename] = S3FileInfo(key, size)
 
     # Always try to use the most recent Discogs export first.
     for id in sorted(ids.keys(), reverse=True):
@@ -41,47 +202,37 @@ def download_discogs_data(output_directory: str) -> List[str]:
         ]
 
         # Ensure that the Discogs export for `id` has all of the data, skipping if it doesn't.
-        if len(ids[id]) != len(data):
+        # Discogs uploads the files of an export one at a time, so a newer export may be partial.
+        # Any auxiliary files next to the data (e.g. a `.torrent`) are ignored.
+        if not all(filename in ids[id] for filename in data):
+            found = f"{sum(filename in ids[id] for filename in data)}/{len(data)}"
+            print(f" --: export {id} is incomplete ({found} files), trying an older export :-- ")
             continue
 
+        files = {filename: ids[id][filename] for filename in data}
         checksums = {}
-        for s3file in ids[id]:
-            filename = s3file.name.split("/")[2]
-
-            def progress(t):
-                def inner(bytes_amount):
-                    t.update(bytes_amount)
-
-                return inner
-
-            path = Path(output_directory, filename)
-            desc = f"{filename:33}"
-            bar_format = "{desc}{percentage:3.0f}%|{bar:80}{r_bar}"
-            with path.open("wb") as f:
-                with tqdm(
-                    desc=desc,
-                    bar_format=bar_format,
-                    ncols=155,
-                    total=s3file.size,
-                    unit="B",
-                    unit_scale=True,
-                ) as t:
-                    s3.download_fileobj(bucket, s3file.name, f, Callback=progress(t))
-
-            hash = sha256()
-            with path.open("rb") as f:
-                for byte_block in iter(lambda: f.read(4096), b""):
-                    hash.update(byte_block)
-                checksums[filename] = hash.hexdigest()
-
-        checksum = Path(output_directory, data[0])
-        with checksum.open("r") as f:
-            while line := f.readline():
-                parts = line.strip().split(" ")
-                correct = "✅"
-                if checksums[parts[1]] != parts[0]:
-                    correct = "❌"
-                print(f"  [{correct}]: checksum for {parts[1]:33}")
+        for filename, s3file in files.items():
+            if stop is not None and stop.is_set():
+                raise DownloadStopped(filename)
+            checksums[filename] = download_file(
+                s3, BUCKET, s3file, output_directory, temp_directory, stop
+            )
+
+        for filename, expected in read_checksums(Path(output_directory, data[0])).items():
+            if checksums[filename] != expected:
+                # Download the file once more, in case it was corrupted in transit.
+                print(f"  [🔁]: checksum mismatch for {filename:33}, downloading again")
+                checksums[filename] = download_file(
+                    s3, BUCKET, files[filename], output_directory, temp_directory, stop
+                )
+            # A second mismatch means the file itself is bad, which must not be extracted.
+            if checksums[filename] != expected:
+                print(f"  [❌]: checksum for {filename:33}")
+                raise RuntimeError(f"checksum mismatch for {filename} after downloading again")
+            print(f"  [✅]: checksum for {filename:33}")
 
         # Since the most recent Discogs export has been downloaded, stop trying to find a complete export.
         return data
+
+    print(" -=: No complete Discogs export is available yet :=- ")
+    return []
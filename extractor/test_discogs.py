@@ -0,0 +1,35 @@
+from hashlib import sha256
+
+from discogs import verify_discogs_data
+
+
+def write_export(directory, tampered=(), missing=()):
+    checksums = []
+    for data_type in ["artists", "labels", "masters", "releases"]:
+        filename = f"discogs_20240101_{data_type}.xml.gz"
+        content = f"<{data_type}></{data_type}>".encode()
+        checksums.append(f"{sha256(content).hexdigest()} {filename}\n")
+        if data_type in tampered:
+            content += b"\n"
+        if data_type not in missing:
+            (directory / filename).write_bytes(content)
+    (directory / "discogs_20240101_CHECKSUM.txt").write_text("".join(checksums))
+
+
+def test_verify_discogs_data(tmp_path):
+    write_export(tmp_path)
+    assert verify_discogs_data(str(tmp_path))
+
+
+def test_verify_discogs_data_tampered(tmp_path, capsys):
+    write_export(tmp_path, tampered=["labels"], missing=["masters"])
+    assert not verify_discogs_data(str(tmp_path))
+    out = capsys.readouterr().out
+    assert "[✅]: checksum for discogs_20240101_artists.xml.gz" in out
+    assert "[❌]: checksum for discogs_20240101_labels.xml.gz" in out
+    assert "[❓]: discogs_20240101_masters.xml.gz" in out
+    assert "[✅]: checksum for discogs_20240101_releases.xml.gz" in out
+
+
+def test_verify_discogs_data_without_checksums(tmp_path):
+    assert not verify_discogs_data(str(tmp_path))
@@ -0,0 +1,53 @@
+from asyncio import run
+from contextlib import asynccontextmanager
+from unittest.mock import patch
+
+import pytest
+
+# Importing connects to neo4j, which isn't needed to test the consumers.
+with patch("neo4j.GraphDatabase.driver"):
+    import graphinator
+    from graphinator import acked, open_channel
+
+
+class FakeChannel:
+    async def set_qos(self, prefetch_count):
+        self.prefetch_count = prefetch_count
+
+
+class FakeConnection:
+    def __init__(self):
+        self.amqp_channel = FakeChannel()
+
+    async def channel(self):
+        return self.amqp_channel
+
+
+class FakeMessage:
+    def __init__(self):
+        self.requeued = None
+
+    @asynccontextmanager
+    async def process(self, requeue=False):
+        try:
+            yield
+        except Exception:
+            self.requeued = requeue
+            raise
+
+
+def test_open_channel_prefetch(monkeypatch):
+    monkeypatch.setattr(graphinator, "AMQP_PREFETCH", 7)
+    connection = FakeConnection()
+    assert run(open_channel(connection)) is connection.amqp_channel
+    assert connection.amqp_channel.prefetch_count == 7
+
+
+def test_acked_requeues_failures():
+    def handler(message):
+        raise RuntimeError("unavailable")
+
+    message = FakeMessage()
+    with pytest.raises(RuntimeError):
+        run(acked(handler)(message))
+    assert message.requeued